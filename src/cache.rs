@@ -0,0 +1,82 @@
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use lru::LruCache;
+use parking_lot::RwLock;
+use nfsserve::nfs;
+
+/// A single resolved directory entry, as produced by `SuperBlock::enhance`.
+#[derive(Clone)]
+pub struct DirEntry {
+    pub name: Vec<u8>,
+    pub fileid: nfs::fileid3,
+    pub attr: nfs::fattr3,
+}
+
+struct Aged<T> {
+    value: T,
+    loaded_at: Instant,
+}
+
+impl<T> Aged<T> {
+    fn new(value: T) -> Self {
+        Self { value, loaded_at: Instant::now() }
+    }
+
+    fn is_fresh(&self, ttl: Duration) -> bool {
+        self.loaded_at.elapsed() < ttl
+    }
+}
+
+/// Bounded, TTL-limited caches for inode attributes and resolved directory
+/// listings, so that repeated `lookup`/`getattr`/`readdir` calls against the
+/// same inode don't re-walk the backing ext4 image every time.
+pub struct Ext4Cache {
+    ttl: Duration,
+    attrs: RwLock<LruCache<nfs::fileid3, Aged<nfs::fattr3>>>,
+    dirs: RwLock<LruCache<nfs::fileid3, Aged<Arc<[DirEntry]>>>>,
+}
+
+impl Ext4Cache {
+    pub fn new(size: usize, ttl: Duration) -> Self {
+        let size = NonZeroUsize::new(size).unwrap_or(NonZeroUsize::new(1).unwrap());
+        Self {
+            ttl,
+            attrs: RwLock::new(LruCache::new(size)),
+            dirs: RwLock::new(LruCache::new(size)),
+        }
+    }
+
+    pub fn get_attr(&self, fileid: nfs::fileid3) -> Option<nfs::fattr3> {
+        let mut attrs = self.attrs.write();
+        match attrs.get(&fileid) {
+            Some(entry) if entry.is_fresh(self.ttl) => Some(entry.value.clone()),
+            Some(_) => {
+                attrs.pop(&fileid);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn put_attr(&self, fileid: nfs::fileid3, attr: nfs::fattr3) {
+        self.attrs.write().put(fileid, Aged::new(attr));
+    }
+
+    pub fn get_dir(&self, dirid: nfs::fileid3) -> Option<Arc<[DirEntry]>> {
+        let mut dirs = self.dirs.write();
+        match dirs.get(&dirid) {
+            Some(entry) if entry.is_fresh(self.ttl) => Some(entry.value.clone()),
+            Some(_) => {
+                dirs.pop(&dirid);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn put_dir(&self, dirid: nfs::fileid3, entries: Arc<[DirEntry]>) {
+        self.dirs.write().put(dirid, Aged::new(entries));
+    }
+}
@@ -1,9 +1,15 @@
-use std::{fs, io::{Seek, self, Read}};
+use std::{fs, os::unix::fs::FileExt, sync::Arc, time::Duration};
 use async_trait::async_trait;
 use ext4::{SuperBlock, Options, Checksums, Enhanced};
 use nfsserve::{vfs, nfs};
 use tracing::{info, debug, error};
 
+mod cache;
+use cache::{DirEntry, Ext4Cache};
+
+pub const DEFAULT_CACHE_SIZE: usize = 1024;
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(120);
+
 fn map_file_type(from: &ext4::FileType) -> nfs::ftype3 {
     match from {
         ext4::FileType::RegularFile => nfs::ftype3::NF3REG,
@@ -20,27 +26,103 @@ fn map_time(from: &ext4::Time) -> nfs::nfstime3 {
     nfs::nfstime3 { seconds: from.epoch_secs, nseconds: from.nanos.unwrap_or(0) }
 }
 
+/// Packs an inode number together with its ext4 generation number into a
+/// single `fileid3`, so a file handle can be recognized as stale once the
+/// inode it named has been deleted and the slot reused for something else.
+fn pack_fileid(inode: u32, generation: u32) -> nfs::fileid3 {
+    ((generation as u64) << 32) | inode as u64
+}
+
+fn unpack_fileid(fileid: nfs::fileid3) -> (u32, u32) {
+    (fileid as u32, (fileid >> 32) as u32)
+}
+
+fn map_ext4_error(e: &ext4::Error) -> nfs::nfsstat3 {
+    use ext4::Error;
+    match e {
+        Error::NotFound(_) | Error::InodeOutOfRange(_) => nfs::nfsstat3::NFS3ERR_STALE,
+        Error::BlockOutOfRange(_) | Error::OutOfBoundsRead(_) | Error::Io(_) => nfs::nfsstat3::NFS3ERR_IO,
+        Error::NotDirectory(_) => nfs::nfsstat3::NFS3ERR_NOTDIR,
+        Error::BadMagic(_) | Error::Corrupt(_) => nfs::nfsstat3::NFS3ERR_IO,
+        _ => nfs::nfsstat3::NFS3ERR_IO,
+    }
+}
+
+/// Slices `entries` into one `readdir` page. `vfs::DirEntry` has no cookie
+/// field independent of `fileid`, so the wire cookie the client echoes back
+/// as `start_after` is exactly the `fileid` of the last entry returned in
+/// the previous page; resuming means finding that `fileid` again rather
+/// than treating the cookie as a raw index.
+fn paginate_dir_entries(entries: &[DirEntry], start_after: nfs::fileid3, max_entries: usize) -> Result<vfs::ReadDirResult, nfs::nfsstat3> {
+    let mut start_index = 0;
+    if start_after > 0 {
+        if let Some(pos) = entries.iter().position(|entry| entry.fileid == start_after) {
+            start_index = pos + 1;
+        } else {
+            return Err(nfs::nfsstat3::NFS3ERR_BAD_COOKIE);
+        }
+    }
+    let remaining_length = entries.len() - start_index;
+    let mut ret = vfs::ReadDirResult {
+        entries: Vec::new(),
+        end: remaining_length <= max_entries
+    };
+    for entry in entries[start_index..].iter().take(max_entries) {
+        ret.entries.push(vfs::DirEntry {
+            fileid: entry.fileid,
+            name: nfs::nfsstring(entry.name.clone()),
+            attr: entry.attr.clone(),
+        });
+    }
+    Ok(ret)
+}
+
 pub struct Ext4FS {
     super_block: SuperBlock<fs::File>,
+    // Separate handle used exclusively for positional reads, so concurrent
+    // `read` calls never contend on the kernel-shared seek offset that
+    // `super_block`'s own handle is used for when resolving metadata.
+    device: fs::File,
+    cache: Ext4Cache,
 }
 
 impl Ext4FS {
     pub fn new_with_path(path: &str) -> Self {
+        Self::new_with_cache(path, DEFAULT_CACHE_SIZE, DEFAULT_CACHE_TTL)
+    }
+
+    pub fn new_with_cache(path: &str, cache_size: usize, cache_ttl: Duration) -> Self {
         let file = fs::File::open(path).expect("path is not an openable file");
+        let device = file.try_clone().expect("failed to duplicate device handle");
         let options = Options { checksums: Checksums::Enabled };
         Self {
-            super_block: SuperBlock::new_with_options(file, &options).expect("did not find a valid ext4 volume")
+            super_block: SuperBlock::new_with_options(file, &options).expect("did not find a valid ext4 volume"),
+            device,
+            cache: Ext4Cache::new(cache_size, cache_ttl),
         }
     }
 
-    fn getattr_sync(&self, id: nfs::fileid3) -> Result<nfs::fattr3, nfs::nfsstat3> {
-        let inode = self.super_block.load_inode(id as u32).map_err(|e| {
-            error!("lookup error: {}", e);
-            nfs::nfsstat3::NFS3ERR_BADHANDLE
+    /// Loads the inode named by `fileid`, rejecting the handle with
+    /// `NFS3ERR_STALE` if the inode's generation has moved on since the
+    /// handle was issued (e.g. the inode was freed and recycled for an
+    /// unrelated file).
+    fn load_inode_checked(&self, fileid: nfs::fileid3) -> Result<ext4::Inode, nfs::nfsstat3> {
+        let (inode_no, generation) = unpack_fileid(fileid);
+        let inode = self.super_block.load_inode(inode_no).map_err(|e| {
+            error!("load_inode error: {}", e);
+            map_ext4_error(&e)
         })?;
+        if inode.stat.generation != generation {
+            error!("stale file handle: inode {} generation {} != expected {}", inode_no, inode.stat.generation, generation);
+            return Err(nfs::nfsstat3::NFS3ERR_STALE);
+        }
+        Ok(inode)
+    }
+
+    fn attr_from_inode(&self, fileid: nfs::fileid3, inode: &ext4::Inode) -> nfs::fattr3 {
         let stat = &inode.stat;
-        Ok(nfs::fattr3 {
-            fileid: id, 
+        nfs::fattr3 {
+            fileid,
             ftype: map_file_type(&stat.extracted_type),
             mode: stat.file_mode as u32,
             nlink: stat.link_count as u32,
@@ -53,7 +135,85 @@ impl Ext4FS {
             atime: map_time(&stat.atime),
             mtime: map_time(&stat.mtime),
             ctime: map_time(&stat.ctime)
-        })
+        }
+    }
+
+    fn getattr_sync(&self, id: nfs::fileid3) -> Result<nfs::fattr3, nfs::nfsstat3> {
+        if let Some(attr) = self.cache.get_attr(id) {
+            return Ok(attr);
+        }
+        let inode = self.load_inode_checked(id)?;
+        let attr = self.attr_from_inode(id, &inode);
+        self.cache.put_attr(id, attr.clone());
+        Ok(attr)
+    }
+
+    fn load_dir_entries(&self, dirid: nfs::fileid3) -> Result<Vec<DirEntry>, nfs::nfsstat3> {
+        let dir = self.load_inode_checked(dirid)?;
+        if let Enhanced::Directory(entries) = self.super_block.enhance(&dir).map_err(|e| {
+            error!("readdir error: {}", e);
+            map_ext4_error(&e)
+        })? {
+            entries.into_iter().map(|entry| {
+                let child = self.super_block.load_inode(entry.inode).map_err(|e| {
+                    error!("readdir error: {}", e);
+                    map_ext4_error(&e)
+                })?;
+                let fileid = pack_fileid(entry.inode, child.stat.generation);
+                let attr = self.attr_from_inode(fileid, &child);
+                self.cache.put_attr(fileid, attr.clone());
+                Ok(DirEntry {
+                    name: entry.name.into_bytes(),
+                    fileid,
+                    attr,
+                })
+            }).collect()
+        } else {
+            Err(nfs::nfsstat3::NFS3ERR_NOTDIR)
+        }
+    }
+
+    fn dir_entries(&self, dirid: nfs::fileid3) -> Result<Arc<[DirEntry]>, nfs::nfsstat3> {
+        if let Some(entries) = self.cache.get_dir(dirid) {
+            return Ok(entries);
+        }
+        let entries: Arc<[DirEntry]> = self.load_dir_entries(dirid)?.into();
+        self.cache.put_dir(dirid, entries.clone());
+        Ok(entries)
+    }
+
+    /// Reads up to `count` bytes starting at `offset` out of `inode`'s
+    /// extents, stopping at the end of the file, using positional reads against `self.device`, so that multiple calls
+    /// running on different Tokio worker threads can proceed in parallel
+    /// without racing on a shared file cursor.
+    fn read_at(&self, inode: &ext4::Inode, offset: u64, count: u32) -> Result<Vec<u8>, nfs::nfsstat3> {
+        let block_size = self.super_block.block_size() as u64;
+        let want = (count as u64).min(inode.stat.size.saturating_sub(offset));
+        let mut data = vec![0u8; want as usize];
+        if want == 0 {
+            return Ok(data);
+        }
+        let extents = self.super_block.extents(inode).map_err(|e| {
+            error!("read error: {}", e);
+            map_ext4_error(&e)
+        })?;
+        let end = offset + want;
+        for extent in extents {
+            let extent_start = extent.block as u64 * block_size;
+            let extent_end = extent_start + extent.len as u64 * block_size;
+            if extent_end <= offset || extent_start >= end {
+                continue;
+            }
+            let read_start = offset.max(extent_start);
+            let read_end = end.min(extent_end);
+            let physical_offset = extent.start * block_size + (read_start - extent_start);
+            let dest = &mut data[(read_start - offset) as usize..(read_end - offset) as usize];
+            self.device.read_at(dest, physical_offset).map_err(|e| {
+                error!("read error: {}", e);
+                nfs::nfsstat3::NFS3ERR_IO
+            })?;
+        }
+        Ok(data)
     }
 }
 
@@ -61,14 +221,51 @@ impl Ext4FS {
 impl vfs::NFSFileSystem for Ext4FS {
     fn root_dir(&self) -> nfs::fileid3 {
         info!(func = "query root dir");
-        self.super_block.root().unwrap().number as nfs::fileid3
+        let root = self.super_block.root().unwrap();
+        pack_fileid(root.number, root.stat.generation)
     }
 
     fn capabilities(&self) -> vfs::VFSCapabilities {
         info!(func = "query capabilities");
         vfs::VFSCapabilities::ReadOnly
     }
-    
+
+    async fn fsstat(&self, id: nfs::fileid3) -> Result<nfs::fsstat3, nfs::nfsstat3> {
+        info!(func = "fsstat", id);
+        let block_size = self.super_block.block_size() as u64;
+        Ok(nfs::fsstat3 {
+            tbytes: self.super_block.total_blocks() * block_size,
+            fbytes: self.super_block.free_blocks() * block_size,
+            abytes: self.super_block.free_blocks() * block_size,
+            tfiles: self.super_block.total_inodes() as u64,
+            ffiles: self.super_block.free_inodes() as u64,
+            afiles: self.super_block.free_inodes() as u64,
+            invarsec: 0,
+        })
+    }
+
+    async fn fsinfo(&self, root_fileid: nfs::fileid3) -> Result<nfs::fsinfo3, nfs::nfsstat3> {
+        info!(func = "fsinfo", root_fileid);
+        let block_size = self.super_block.block_size() as u32;
+        // rtmax/wtmax are the hard per-RPC size ceiling, not the preferred
+        // size, so they should be a generous multiple of the block size
+        // rather than the block size itself (which would cap every READ
+        // and WRITE at one block).
+        let max_transfer_size = block_size * 256;
+        Ok(nfs::fsinfo3 {
+            rtmax: max_transfer_size,
+            rtpref: block_size,
+            rtmult: block_size,
+            wtmax: max_transfer_size,
+            wtpref: block_size,
+            wtmult: block_size,
+            dtpref: block_size,
+            maxfilesize: u32::MAX as u64 * block_size as u64,
+            time_delta: nfs::nfstime3 { seconds: 1, nseconds: 0 },
+            properties: nfs::FSF_HOMOGENEOUS,
+        })
+    }
+
     async fn write(&self, _id: nfs::fileid3, _offset: u64, _data: &[u8]) -> Result<nfs::fattr3, nfs::nfsstat3> {
         info!(func = "write");
         Err(nfs::nfsstat3::NFS3ERR_ROFS)
@@ -96,82 +293,27 @@ impl vfs::NFSFileSystem for Ext4FS {
 
     async fn lookup(&self, dirid: nfs::fileid3, filename: &nfs::filename3) -> Result<nfs::fileid3, nfs::nfsstat3> {
         info!(func = "lookup", dirid, ?filename);
-        let dir = self.super_block.load_inode(dirid as u32).map_err(|e| {
-            error!("lookup error: {}", e);
-            nfs::nfsstat3::NFS3ERR_BADHANDLE
-        })?;
-        if let Enhanced::Directory(entries) = self.super_block.enhance(&dir).map_err(|e| {
-            error!("lookup error: {}", e);
-            nfs::nfsstat3::NFS3ERR_BADHANDLE
-        })? {
-            if let Some(entry) = entries.into_iter().find(|entry| entry.name.as_bytes() == &filename.0) {
-                Ok(entry.inode as nfs::fileid3)
-            } else {
-                Err(nfs::nfsstat3::NFS3ERR_NOENT)
-            }
-        } else {
-            Err(nfs::nfsstat3::NFS3ERR_NOTDIR)
-        }
+        let entries = self.dir_entries(dirid)?;
+        entries.iter()
+            .find(|entry| entry.name == filename.0)
+            .map(|entry| entry.fileid)
+            .ok_or(nfs::nfsstat3::NFS3ERR_NOENT)
     }
 
     async fn read(&self, id: nfs::fileid3, offset: u64, count: u32) -> Result<(Vec<u8>, bool), nfs::nfsstat3> {
         info!(func = "read", id, offset, count);
-        let inode = self.super_block.load_inode(id as u32).map_err(|e| {
-            error!("read error: {}", e);
-            nfs::nfsstat3::NFS3ERR_BADHANDLE
-        })?;
-        let mut reader = self.super_block.open(&inode).map_err(|e| {
-            error!("read error: {}", e);
-            nfs::nfsstat3::NFS3ERR_BADHANDLE
-        })?;
-        reader.seek(io::SeekFrom::Start(offset)).map_err(|e| {
-            error!("read error: {}", e);
-            nfs::nfsstat3::NFS3ERR_IO
-        })?;
-        let mut data = vec![0; count as usize];
-        let read_count = reader.read(&mut data).map_err(|e| {
-            error!("read error: {}", e);
-            nfs::nfsstat3::NFS3ERR_IO
-        })?;
-        data.truncate(read_count);
-        Ok((data, read_count as u64 + offset < inode.stat.size))
+        let inode = self.load_inode_checked(id)?;
+        let data = self.read_at(&inode, offset, count)?;
+        let more_data = offset + data.len() as u64 < inode.stat.size;
+        Ok((data, more_data))
     }
 
     async fn readdir(&self, dirid: nfs::fileid3, start_after: nfs::fileid3, max_entries: usize) -> Result<vfs::ReadDirResult, nfs::nfsstat3> {
         info!(func = "readdir", dirid, start_after, max_entries);
-        let dir = self.super_block.load_inode(dirid as u32).map_err(|e| {
-            error!("lookup error: {}", e);
-            nfs::nfsstat3::NFS3ERR_BADHANDLE
-        })?;
-        if let Enhanced::Directory(entries) = self.super_block.enhance(&dir).map_err(|e| {
-            error!("lookup error: {}", e);
-            nfs::nfsstat3::NFS3ERR_BADHANDLE
-        })? {
-            let mut start_index = 0;
-            if start_after > 0 {
-                if let Some(pos) = entries.iter().position(|entry| entry.inode == start_after as u32) {
-                    start_index = pos + 1;
-                } else {
-                    return Err(nfs::nfsstat3::NFS3ERR_BAD_COOKIE);
-                }
-            }
-            let remaining_length = entries.len() - start_index;
-            let mut ret = vfs::ReadDirResult {
-                entries: Vec::new(),
-                end: remaining_length <= max_entries
-            };
-            for entry in entries[start_index..].iter().take(max_entries) {
-                ret.entries.push(vfs::DirEntry {
-                    fileid: entry.inode as nfs::fileid3,
-                    name: nfs::nfsstring(entry.name.clone().into_bytes()),
-                    attr: self.getattr_sync(entry.inode as nfs::fileid3)?,
-                });
-            }
-            debug!("readdir read {} entries", ret.entries.len());
-            Ok(ret)
-        } else {
-            Err(nfs::nfsstat3::NFS3ERR_NOTDIR)
-        }
+        let entries = self.dir_entries(dirid)?;
+        let ret = paginate_dir_entries(&entries, start_after, max_entries)?;
+        debug!("readdir read {} entries", ret.entries.len());
+        Ok(ret)
     }
 
     async fn remove(&self, _dirid: nfs::fileid3, _filename: &nfs::filename3) -> Result<(), nfs::nfsstat3> {
@@ -194,8 +336,69 @@ impl vfs::NFSFileSystem for Ext4FS {
         Err(nfs::nfsstat3::NFS3ERR_ROFS)
     }
 
-    async fn readlink(&self, _id: nfs::fileid3) -> Result<nfs::nfspath3, nfs::nfsstat3> {
-        info!(func = "readlink");
-        Err(nfs::nfsstat3::NFS3ERR_NOTSUPP)
+    async fn readlink(&self, id: nfs::fileid3) -> Result<nfs::nfspath3, nfs::nfsstat3> {
+        info!(func = "readlink", id);
+        let inode = self.load_inode_checked(id)?;
+        match self.super_block.enhance(&inode).map_err(|e| {
+            error!("readlink error: {}", e);
+            map_ext4_error(&e)
+        })? {
+            Enhanced::SymbolicLink(target) => Ok(nfs::nfspath3(target.into_bytes())),
+            _ => Err(nfs::nfsstat3::NFS3ERR_INVAL),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_entry(fileid: nfs::fileid3, name: &str) -> DirEntry {
+        DirEntry {
+            name: name.as_bytes().to_vec(),
+            fileid,
+            attr: nfs::fattr3 {
+                fileid,
+                ftype: nfs::ftype3::NF3REG,
+                mode: 0o644,
+                nlink: 1,
+                uid: 0,
+                gid: 0,
+                size: 0,
+                used: 0,
+                rdev: Default::default(),
+                fsid: 0,
+                atime: nfs::nfstime3 { seconds: 0, nseconds: 0 },
+                mtime: nfs::nfstime3 { seconds: 0, nseconds: 0 },
+                ctime: nfs::nfstime3 { seconds: 0, nseconds: 0 },
+            },
+        }
+    }
+
+    #[test]
+    fn paginate_dir_entries_spans_multiple_readdir_calls() {
+        let entries: Vec<DirEntry> = (0..10)
+            .map(|i| test_entry(i + 1, &format!("file{i}")))
+            .collect();
+
+        let first = paginate_dir_entries(&entries, 0, 5).unwrap();
+        assert_eq!(first.entries.len(), 5);
+        assert!(!first.end);
+        assert_eq!(first.entries[0].name.0, b"file0");
+        assert_eq!(first.entries[4].name.0, b"file4");
+
+        let cookie = first.entries.last().unwrap().fileid;
+        let second = paginate_dir_entries(&entries, cookie, 5).unwrap();
+        assert_eq!(second.entries.len(), 5);
+        assert!(second.end);
+        assert_eq!(second.entries[0].name.0, b"file5");
+        assert_eq!(second.entries[4].name.0, b"file9");
+    }
+
+    #[test]
+    fn paginate_dir_entries_rejects_unknown_cookie() {
+        let entries = vec![test_entry(1, "only")];
+        let err = paginate_dir_entries(&entries, 42, 5).unwrap_err();
+        assert_eq!(err, nfs::nfsstat3::NFS3ERR_BAD_COOKIE);
     }
 }
\ No newline at end of file
@@ -1,5 +1,7 @@
+use std::time::Duration;
+
 use tokio;
-use ext4nfs::Ext4FS;
+use ext4nfs::{Ext4FS, DEFAULT_CACHE_SIZE, DEFAULT_CACHE_TTL};
 use nfsserve::tcp::{self, NFSTcp};
 use clap::Parser;
 
@@ -8,14 +10,20 @@ struct Args {
     #[arg(long, default_value_t = 11111)]
     port: u16,
 
+    #[arg(long, default_value_t = DEFAULT_CACHE_SIZE, help = "Number of inode/directory entries to keep cached")]
+    cache_size: usize,
+
+    #[arg(long, default_value_t = DEFAULT_CACHE_TTL.as_secs(), help = "Seconds before a cached entry is re-validated")]
+    cache_ttl: u64,
+
     #[arg(required = true, help = "Device file path")]
     path: String,
 }
 
 fn main() {
     let args = Args::parse();
-    let my_fs = Ext4FS::new_with_path(&args.path);
-    tokio::runtime::Builder::new_current_thread()
+    let my_fs = Ext4FS::new_with_cache(&args.path, args.cache_size, Duration::from_secs(args.cache_ttl));
+    tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
         .unwrap()